@@ -0,0 +1,202 @@
+//! `textDocument/codeAction` support for fixing formatting surgically
+//! instead of reflowing the whole message: rewrap just the paragraph under
+//! the cursor, nudge its quote depth, or normalize a signature delimiter.
+
+use std::collections::HashMap;
+
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, CodeActionOrCommand, Position, Range, TextEdit, Url, WorkspaceEdit,
+};
+
+use crate::config::Config;
+use crate::encoding::PositionEncoding;
+use crate::{classify_lines, quote_level, reformat_body, LineKind};
+
+pub(crate) fn actions_for(
+    uri: &Url,
+    text: &str,
+    range: Range,
+    encoding: PositionEncoding,
+    config: &Config,
+) -> Vec<CodeActionOrCommand> {
+    let lines = text.lines().collect::<Vec<_>>();
+    let cursor_line = range.start.line as usize;
+    let Some((kind, _)) = classify_lines(text).get(cursor_line).copied() else {
+        return vec![];
+    };
+
+    let mut actions = vec![];
+    match kind {
+        LineKind::Header => {}
+        LineKind::Body => {
+            let (start, end) = paragraph_bounds(&lines, cursor_line, &config.quote_prefix);
+            let block_range = line_block_range(&lines, start, end, encoding);
+
+            actions.push(rewrap_action(uri, &lines, start, end, block_range, config));
+            actions.push(change_quote_depth_action(
+                uri,
+                &lines,
+                start,
+                end,
+                block_range,
+                config,
+                true,
+            ));
+            if quote_level(lines[cursor_line], &config.quote_prefix) > 0 {
+                actions.push(change_quote_depth_action(
+                    uri,
+                    &lines,
+                    start,
+                    end,
+                    block_range,
+                    config,
+                    false,
+                ));
+            }
+        }
+        LineKind::Signature => {
+            if lines[cursor_line] == "--" {
+                actions.push(normalize_signature_action(uri, &lines, cursor_line));
+            }
+        }
+    }
+
+    actions
+}
+
+// Find the contiguous run of same-quote-level, non-blank lines around
+// `line_idx` -- the same notion of "paragraph" that `reformat` groups.
+fn paragraph_bounds(lines: &[&str], line_idx: usize, quote_prefix: &str) -> (usize, usize) {
+    let level = quote_level(lines[line_idx], quote_prefix);
+
+    let mut start = line_idx;
+    while start > 0
+        && !lines[start - 1].trim().is_empty()
+        && quote_level(lines[start - 1], quote_prefix) == level
+    {
+        start -= 1;
+    }
+
+    let mut end = line_idx;
+    while end + 1 < lines.len()
+        && !lines[end + 1].trim().is_empty()
+        && quote_level(lines[end + 1], quote_prefix) == level
+    {
+        end += 1;
+    }
+
+    (start, end)
+}
+
+fn line_block_range(lines: &[&str], start: usize, end: usize, encoding: PositionEncoding) -> Range {
+    if end + 1 < lines.len() {
+        Range::new(
+            Position::new(start as u32, 0),
+            Position::new((end + 1) as u32, 0),
+        )
+    } else {
+        Range::new(
+            Position::new(start as u32, 0),
+            Position::new(end as u32, encoding.line_length_in_chars(lines[end])),
+        )
+    }
+}
+
+fn rewrap_action(
+    uri: &Url,
+    lines: &[&str],
+    start: usize,
+    end: usize,
+    range: Range,
+    config: &Config,
+) -> CodeActionOrCommand {
+    let new_text = reformat_body(&lines[start..=end], config);
+    build_action("Rewrap paragraph", uri, range, new_text)
+}
+
+fn change_quote_depth_action(
+    uri: &Url,
+    lines: &[&str],
+    start: usize,
+    end: usize,
+    range: Range,
+    config: &Config,
+    increase: bool,
+) -> CodeActionOrCommand {
+    let adjusted = lines[start..=end]
+        .iter()
+        .map(|line| {
+            if increase {
+                format!("{}{line}", config.quote_prefix)
+            } else {
+                strip_one_quote_level(line, &config.quote_prefix)
+            }
+        })
+        .collect::<Vec<_>>();
+    let adjusted_refs = adjusted.iter().map(String::as_str).collect::<Vec<_>>();
+    let new_text = reformat_body(&adjusted_refs, config);
+
+    let title = if increase {
+        "Increase quote level"
+    } else {
+        "Decrease quote level"
+    };
+    build_action(title, uri, range, new_text)
+}
+
+fn strip_one_quote_level(line: &str, quote_prefix: &str) -> String {
+    if !quote_prefix.is_empty() {
+        if let Some(rest) = line.strip_prefix(quote_prefix) {
+            return rest.to_string();
+        }
+    }
+    // Fall back to stripping a bare `>` (and the conventional following
+    // space) in case the line was quoted before `quote_prefix` changed.
+    let rest = line.strip_prefix('>').unwrap_or(line);
+    rest.strip_prefix(' ').unwrap_or(rest).to_string()
+}
+
+fn normalize_signature_action(uri: &Url, lines: &[&str], line_idx: usize) -> CodeActionOrCommand {
+    // A single edit replacing the whole "--" span, rather than a separate
+    // empty-range insert plus a replace touching the same point: two edits
+    // that meet at one position have client-dependent ordering, so the
+    // blank line and the delimiter rewrite could land in either order.
+    let new_text = if line_idx == 0 || !lines[line_idx - 1].trim().is_empty() {
+        "\n-- "
+    } else {
+        "-- "
+    };
+    let edits = vec![TextEdit {
+        range: Range::new(
+            Position::new(line_idx as u32, 0),
+            Position::new(line_idx as u32, 2),
+        ),
+        new_text: new_text.to_string(),
+    }];
+
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), edits);
+    CodeActionOrCommand::CodeAction(CodeAction {
+        title: "Normalize signature delimiter".to_string(),
+        kind: Some(CodeActionKind::QUICKFIX),
+        edit: Some(WorkspaceEdit {
+            changes: Some(changes),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
+
+fn build_action(title: &str, uri: &Url, range: Range, new_text: String) -> CodeActionOrCommand {
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![TextEdit { range, new_text }]);
+    CodeActionOrCommand::CodeAction(CodeAction {
+        title: title.to_string(),
+        kind: Some(CodeActionKind::REFACTOR_REWRITE),
+        edit: Some(WorkspaceEdit {
+            changes: Some(changes),
+            ..Default::default()
+        }),
+        ..Default::default()
+    })
+}
@@ -0,0 +1,41 @@
+//! User-tunable formatting behaviour.
+//!
+//! Settings arrive as the `initialization_options` sent with `initialize`
+//! and can be refreshed later via `workspace/didChangeConfiguration`, so
+//! mailing lists and corporate style guides that differ from the defaults
+//! (72 vs. 78 columns, French-style `» ` quoting, etc.) don't require
+//! rebuilding the binary.
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::LINE_LENGTH;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub(crate) struct Config {
+    pub(crate) line_length: usize,
+    pub(crate) quote_prefix: String,
+    pub(crate) reflow_signature: bool,
+    pub(crate) detect_format_flowed: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            line_length: LINE_LENGTH,
+            quote_prefix: "> ".to_string(),
+            reflow_signature: false,
+            detect_format_flowed: false,
+        }
+    }
+}
+
+impl Config {
+    /// Parse a `Config` out of a JSON value such as `initialization_options`
+    /// or the `settings` of a `didChangeConfiguration` notification. Missing
+    /// fields and unparsable values fall back to the defaults.
+    pub(crate) fn from_value(value: Value) -> Self {
+        serde_json::from_value(value).unwrap_or_default()
+    }
+}
@@ -0,0 +1,95 @@
+//! Proactive analysis of a stored document, run after `did_open` and every
+//! `did_change` so problems are surfaced before the user explicitly asks for
+//! a format. Reuses the same Header/Body/Signature state machine as
+//! `reformat_entire_doc` so header folding is never mistaken for a body
+//! overflow.
+
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, Position, Range};
+
+use crate::config::Config;
+use crate::encoding::PositionEncoding;
+use crate::{classify_lines, quote_level, LineKind};
+
+/// Analyze `text` and return the diagnostics that currently apply. An empty
+/// vec means the document is clean and any previously-published diagnostics
+/// for it should be cleared. Ranges are built in `encoding`'s units, the
+/// same way `range_formatting` and `code_actions` convert positions, so a
+/// line's accented or non-BMP characters don't throw off the highlight
+/// under a non-default `positionEncoding`.
+pub(crate) fn analyze(text: &str, config: &Config, encoding: PositionEncoding) -> Vec<Diagnostic> {
+    let mut diagnostics = vec![];
+    let mut prev_body_line: Option<(u32, usize)> = None;
+
+    for (line_no, (kind, line)) in classify_lines(text).into_iter().enumerate() {
+        let line_no = line_no as u32;
+        match kind {
+            LineKind::Header => {}
+            LineKind::Body => {
+                let len = line.chars().count();
+                let line_end_units = encoding.line_length_in_chars(line);
+                if len > config.line_length {
+                    let limit_units = encoding.units_at_char_count(line, config.line_length);
+                    diagnostics.push(Diagnostic {
+                        range: Range::new(
+                            Position::new(line_no, limit_units),
+                            Position::new(line_no, line_end_units),
+                        ),
+                        severity: Some(DiagnosticSeverity::WARNING),
+                        message: format!(
+                            "line exceeds the {}-column wrap limit by {} characters",
+                            config.line_length,
+                            len - config.line_length
+                        ),
+                        ..Default::default()
+                    });
+                }
+
+                if line.trim().is_empty() {
+                    prev_body_line = None;
+                } else {
+                    let level = quote_level(line, &config.quote_prefix);
+                    if let Some((prev_line, prev_level)) = prev_body_line {
+                        // Only flag a depth *increase* mid-paragraph, e.g.
+                        // `>` followed directly by `> >` -- that's the
+                        // misattribution shape the request describes. A
+                        // decrease (a quoted line followed by the reply's
+                        // own unquoted text, with no blank line between) is
+                        // the ordinary top-posted reply shape and would
+                        // spam a diagnostic on nearly every message.
+                        if prev_line + 1 == line_no && level > prev_level {
+                            diagnostics.push(Diagnostic {
+                                range: Range::new(
+                                    Position::new(line_no, 0),
+                                    Position::new(line_no, line_end_units),
+                                ),
+                                severity: Some(DiagnosticSeverity::INFORMATION),
+                                message: "quote depth increases with no blank line separating it \
+                                          from the previous paragraph; quoting may be \
+                                          misattributed"
+                                    .into(),
+                                ..Default::default()
+                            });
+                        }
+                    }
+                    prev_body_line = Some((line_no, level));
+                }
+            }
+            LineKind::Signature => {
+                if line == "--" {
+                    diagnostics.push(Diagnostic {
+                        range: Range::new(
+                            Position::new(line_no, 0),
+                            Position::new(line_no, encoding.line_length_in_chars(line)),
+                        ),
+                        severity: Some(DiagnosticSeverity::HINT),
+                        message: "signature delimiter should be \"-- \" (with a trailing space)"
+                            .into(),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    }
+
+    diagnostics
+}
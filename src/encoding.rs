@@ -0,0 +1,169 @@
+//! Helpers for dealing with the LSP `Position.character` field, which is
+//! specified to be a count of UTF-16 code units by default but may be
+//! negotiated down to UTF-8 bytes if both client and server support it.
+//!
+//! See https://microsoft.github.io/language-server-protocol/specifications/lsp/3.17/specification/#textDocuments
+//! under "PositionEncodingKind" for the negotiation rules this implements.
+
+use tower_lsp::lsp_types::{Position, PositionEncodingKind};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PositionEncoding {
+    Utf8,
+    Utf16,
+}
+
+impl PositionEncoding {
+    /// Pick the encoding we'll use for the lifetime of the session, given the
+    /// `general.position_encodings` the client offered during `initialize`.
+    /// We prefer UTF-8 since it lets us skip the UTF-16 walk entirely, but
+    /// UTF-16 is the protocol default and must work if the client doesn't
+    /// advertise anything else.
+    pub fn negotiate(offered: Option<&[PositionEncodingKind]>) -> Self {
+        match offered {
+            Some(kinds) if kinds.contains(&PositionEncodingKind::UTF8) => PositionEncoding::Utf8,
+            _ => PositionEncoding::Utf16,
+        }
+    }
+
+    pub fn as_lsp(&self) -> PositionEncodingKind {
+        match self {
+            PositionEncoding::Utf8 => PositionEncodingKind::UTF8,
+            PositionEncoding::Utf16 => PositionEncodingKind::UTF16,
+        }
+    }
+
+    /// Convert a `character` offset on a single line into a byte offset into
+    /// that line's string. `line` must not contain a line terminator.
+    pub fn char_to_byte_in_line(&self, line: &str, character: u32) -> usize {
+        match self {
+            PositionEncoding::Utf8 => (character as usize).min(line.len()),
+            PositionEncoding::Utf16 => {
+                let mut units = 0u32;
+                let mut bytes = 0usize;
+                for ch in line.chars() {
+                    if units >= character {
+                        break;
+                    }
+                    units += ch.len_utf16() as u32;
+                    bytes += ch.len_utf8();
+                }
+                bytes
+            }
+        }
+    }
+
+    /// Convert an LSP `Position` into a byte offset within the whole
+    /// document `text`. Positions past the end of the document clamp to
+    /// `text.len()`.
+    pub fn position_to_byte_offset(&self, text: &str, position: Position) -> usize {
+        let Some(line_start) = line_byte_offset(text, position.line) else {
+            return text.len();
+        };
+        let rest = &text[line_start..];
+        let line_end = rest.find('\n').unwrap_or(rest.len());
+        let line = &rest[..line_end];
+
+        line_start + self.char_to_byte_in_line(line, position.character)
+    }
+
+    /// The length of `line` expressed in the negotiated encoding's units,
+    /// i.e. the `character` value of a `Position` pointing at its end.
+    pub fn line_length_in_chars(&self, line: &str) -> u32 {
+        match self {
+            PositionEncoding::Utf8 => line.len() as u32,
+            PositionEncoding::Utf16 => line.chars().map(|c| c.len_utf16() as u32).sum(),
+        }
+    }
+
+    /// Convert a Unicode scalar count (e.g. from `str::chars().count()`)
+    /// into the `character` value of a `Position` pointing at the same
+    /// spot in `line`, so column counts computed in `char`s can be used to
+    /// build diagnostic ranges in the negotiated encoding. `char_count`
+    /// past the end of `line` clamps to the line's full length in units.
+    pub fn units_at_char_count(&self, line: &str, char_count: usize) -> u32 {
+        match self {
+            PositionEncoding::Utf8 => line
+                .chars()
+                .take(char_count)
+                .map(char::len_utf8)
+                .sum::<usize>() as u32,
+            PositionEncoding::Utf16 => line
+                .chars()
+                .take(char_count)
+                .map(|c| c.len_utf16() as u32)
+                .sum(),
+        }
+    }
+}
+
+fn line_byte_offset(text: &str, line: u32) -> Option<usize> {
+    if line == 0 {
+        return Some(0);
+    }
+    text.match_indices('\n')
+        .nth(line as usize - 1)
+        .map(|(idx, _)| idx + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn utf16_walks_a_two_byte_char_as_one_unit() {
+        // "café" -- 'é' is 2 bytes in UTF-8 but a single UTF-16 code unit,
+        // so a client counting in UTF-16 code units must still land right
+        // after it, not one unit short.
+        let line = "café";
+        let byte = PositionEncoding::Utf16.char_to_byte_in_line(line, 4);
+        assert_eq!(byte, line.len());
+        assert_eq!(&line[..byte], "café");
+    }
+
+    #[test]
+    fn utf16_walks_a_surrogate_pair_as_two_units() {
+        // "🎉" is 4 bytes in UTF-8 but encodes as a surrogate pair -- two
+        // UTF-16 code units -- so the '!' after it starts at character 2
+        // (byte 4) and the line ends at character 3 (byte 5). A position
+        // between the two units (character 1) rounds forward past the
+        // emoji rather than landing inside its bytes.
+        let line = "🎉!";
+        assert_eq!(PositionEncoding::Utf16.char_to_byte_in_line(line, 0), 0);
+        assert_eq!(PositionEncoding::Utf16.char_to_byte_in_line(line, 2), 4);
+        assert_eq!(PositionEncoding::Utf16.char_to_byte_in_line(line, 3), 5);
+    }
+
+    #[test]
+    fn utf8_encoding_treats_character_as_a_raw_byte_offset() {
+        let line = "café";
+        assert_eq!(PositionEncoding::Utf8.char_to_byte_in_line(line, 3), 3);
+        assert_eq!(
+            PositionEncoding::Utf8.char_to_byte_in_line(line, 99),
+            line.len()
+        );
+    }
+
+    #[test]
+    fn units_at_char_count_accounts_for_wide_characters() {
+        // "café!" -- the 5th char ('!') starts after 4 Unicode scalars, but
+        // only 4 UTF-16 units (since 'é' is 1 unit) or 5 UTF-8 bytes
+        // (since 'é' is 2 bytes).
+        let line = "café!";
+        assert_eq!(PositionEncoding::Utf16.units_at_char_count(line, 4), 4);
+        assert_eq!(PositionEncoding::Utf8.units_at_char_count(line, 4), 5);
+        assert_eq!(
+            PositionEncoding::Utf16.units_at_char_count(line, 99),
+            PositionEncoding::Utf16.line_length_in_chars(line)
+        );
+    }
+
+    #[test]
+    fn position_to_byte_offset_finds_non_ascii_content_on_later_lines() {
+        let text = "hello\ncafé Résumé\nworld";
+        // "café " occupies UTF-16 units 0..5; "Résumé" starts right after.
+        let pos = Position::new(1, 5);
+        let offset = PositionEncoding::Utf16.position_to_byte_offset(text, pos);
+        assert_eq!(&text[offset..], "Résumé\nworld");
+    }
+}
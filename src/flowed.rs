@@ -0,0 +1,158 @@
+//! format=flowed (RFC 3676) aware reflow.
+//!
+//! Unlike the default reflow, which greedily rejoins every line at a given
+//! quote depth, flowed text distinguishes "soft" breaks (a line ending in a
+//! single trailing space, which may be rejoined and rewrapped with the next
+//! line) from "hard" breaks (no trailing space, preserved verbatim) so
+//! tables, poetry, code snippets and signatures survive a reflow. Gated
+//! behind `Config::detect_format_flowed`.
+
+use crate::config::Config;
+use crate::strip_quote_markers;
+
+/// Strip quote markers and RFC 3676 space-stuffing off a raw physical line,
+/// returning its quote depth, de-stuffed content, and whether the line ends
+/// in a soft break.
+fn parse_line(line: &str, quote_prefix: &str) -> (usize, String, bool) {
+    let (depth, rest) = strip_quote_markers(line, quote_prefix);
+    // What remains of a genuinely space-stuffed line still carries its one
+    // extra leading space (e.g. a body line or "From " that was stuffed).
+    let content = rest.strip_prefix(' ').unwrap_or(rest);
+    let is_soft = !line.is_empty() && line.ends_with(' ');
+    (depth, content.to_string(), is_soft)
+}
+
+pub(crate) fn reformat_flowed(input: &[&str], config: &Config) -> String {
+    let parsed = input
+        .iter()
+        .map(|l| parse_line(l, &config.quote_prefix))
+        .collect::<Vec<_>>();
+    let mut ret = String::new();
+
+    let mut i = 0;
+    while i < parsed.len() {
+        let (depth, ref content, is_soft) = parsed[i];
+        if content.trim().is_empty() {
+            ret.push('\n');
+            i += 1;
+            continue;
+        }
+
+        if !is_soft {
+            // A hard break stands alone: tables, code, poetry and
+            // signatures rely on their exact spacing and indentation
+            // surviving byte-for-byte (de-stuffing aside), so emit it
+            // verbatim instead of word-wrapping it.
+            emit_hard_line(&mut ret, depth, content, config);
+            i += 1;
+            continue;
+        }
+
+        // Merge this line with every following line that the current line
+        // soft-breaks into, as long as the quote depth doesn't change --
+        // a depth transition always forces a break even across a soft line.
+        let mut group_text = content.trim().to_string();
+        let mut j = i;
+        while parsed[j].2
+            && j + 1 < parsed.len()
+            && parsed[j + 1].0 == depth
+            && !parsed[j + 1].1.trim().is_empty()
+        {
+            j += 1;
+            group_text.push(' ');
+            group_text.push_str(parsed[j].1.trim());
+        }
+
+        emit_paragraph(&mut ret, depth, &group_text, config);
+        i = j + 1;
+    }
+
+    ret
+}
+
+// Emit a single hard-broken line as-is, only re-stuffing it so it isn't
+// misread as quoting or mbox framing on the next parse.
+fn emit_hard_line(ret: &mut String, depth: usize, content: &str, config: &Config) {
+    let prefix = config.quote_prefix.repeat(depth);
+    ret.push_str(&prefix);
+    ret.push_str(&stuff(content));
+    ret.push('\n');
+}
+
+// Re-apply RFC 3676 space-stuffing to a line of content about to be
+// emitted, so a line that happens to start with a space or "From " isn't
+// misread as quoting or mbox framing on the next parse.
+fn stuff(content: &str) -> String {
+    if content.starts_with(' ') || content.starts_with("From ") {
+        format!(" {content}")
+    } else {
+        content.to_string()
+    }
+}
+
+// Rewrap `text` at `config.line_length`, re-stuffing lines that would
+// otherwise be misread and terminating every line but the last with the
+// trailing space that marks it as a soft (flowable) break.
+fn emit_paragraph(ret: &mut String, depth: usize, text: &str, config: &Config) {
+    let prefix = config.quote_prefix.repeat(depth);
+    let mut lines = vec![];
+    let mut lineacc = String::new();
+
+    for word in text.split_ascii_whitespace() {
+        let candidate_len =
+            prefix.len() + lineacc.len() + usize::from(!lineacc.is_empty()) + word.len();
+        if !lineacc.is_empty() && candidate_len > config.line_length {
+            lines.push(std::mem::take(&mut lineacc));
+        }
+        if !lineacc.is_empty() {
+            lineacc.push(' ');
+        }
+        lineacc.push_str(word);
+    }
+    lines.push(lineacc);
+
+    let last = lines.len() - 1;
+    for (idx, content) in lines.iter().enumerate() {
+        ret.push_str(&prefix);
+        ret.push_str(&stuff(content));
+        if idx != last {
+            ret.push(' ');
+        }
+        ret.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        Config {
+            detect_format_flowed: true,
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn soft_broken_lines_are_rejoined_and_rewrapped() {
+        let input = ["This is a ", "soft broken line."];
+        let out = reformat_flowed(&input, &config());
+        assert_eq!(out, "This is a soft broken line.\n");
+    }
+
+    #[test]
+    fn hard_broken_lines_are_preserved_verbatim() {
+        // A table and an indented code line: word-wrapping either one
+        // would collapse the column alignment and strip the indentation.
+        let input = ["col1    col2", "  indented code"];
+        let out = reformat_flowed(&input, &config());
+        assert_eq!(out, "col1    col2\n  indented code\n");
+    }
+
+    #[test]
+    fn a_hard_line_does_not_absorb_a_following_soft_paragraph() {
+        let input = ["col1    col2", "a soft ", "paragraph."];
+        let out = reformat_flowed(&input, &config());
+        assert_eq!(out, "col1    col2\na soft paragraph.\n");
+    }
+}
@@ -1,34 +1,66 @@
+mod code_actions;
+mod config;
+mod diagnostics;
+mod encoding;
+mod flowed;
+
 use std::collections::HashMap;
 
+use config::Config;
+use encoding::PositionEncoding;
 use tokio::sync::Mutex;
 use tower_lsp::{
     jsonrpc::Result,
     lsp_types::{
-        DidChangeTextDocumentParams, DidCloseTextDocumentParams, DidOpenTextDocumentParams,
-        DocumentFormattingParams, DocumentRangeFormattingParams, InitializeParams,
-        InitializeResult, InitializedParams, MessageType, OneOf, Position, Range,
-        ServerCapabilities, ServerInfo, TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit,
-        Url,
+        CodeActionParams, CodeActionProviderCapability, CodeActionResponse,
+        DidChangeConfigurationParams, DidChangeTextDocumentParams, DidCloseTextDocumentParams,
+        DidOpenTextDocumentParams, DocumentFormattingParams, DocumentRangeFormattingParams,
+        InitializeParams, InitializeResult, InitializedParams, MessageType, OneOf, Position,
+        Range, Registration, ServerCapabilities, ServerInfo, TextDocumentContentChangeEvent,
+        TextDocumentSyncCapability, TextDocumentSyncKind, TextEdit, Url,
     },
     Client, LanguageServer, LspService, Server,
 };
 
+#[derive(Debug, Default)]
+struct Document {
+    text: String,
+    version: i32,
+}
+
 #[derive(Debug)]
 struct Backend {
     client: Client,
-    documents: Mutex<HashMap<Url, String>>,
+    documents: Mutex<HashMap<Url, Document>>,
+    position_encoding: Mutex<PositionEncoding>,
+    config: Mutex<Config>,
 }
 
 #[tower_lsp::async_trait]
 impl LanguageServer for Backend {
-    async fn initialize(&self, _: InitializeParams) -> Result<InitializeResult> {
+    async fn initialize(&self, params: InitializeParams) -> Result<InitializeResult> {
+        let offered = params
+            .capabilities
+            .general
+            .as_ref()
+            .and_then(|g| g.position_encodings.as_deref());
+        let encoding = PositionEncoding::negotiate(offered);
+        *self.position_encoding.lock().await = encoding;
+
+        *self.config.lock().await = params
+            .initialization_options
+            .map(Config::from_value)
+            .unwrap_or_default();
+
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                    TextDocumentSyncKind::INCREMENTAL,
                 )),
                 document_formatting_provider: Some(OneOf::Left(true)),
                 document_range_formatting_provider: Some(OneOf::Left(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
+                position_encoding: Some(encoding.as_lsp()),
                 ..Default::default()
             },
             server_info: Some(ServerInfo {
@@ -42,24 +74,68 @@ impl LanguageServer for Backend {
         self.client
             .log_message(MessageType::INFO, "server initialized!")
             .await;
+
+        // Ask the client to notify us of config changes; not every client
+        // supports dynamic registration, so failure here is non-fatal.
+        let registration = Registration {
+            id: "hanumail-config".to_string(),
+            method: "workspace/didChangeConfiguration".to_string(),
+            register_options: None,
+        };
+        if let Err(e) = self.client.register_capability(vec![registration]).await {
+            self.client
+                .log_message(
+                    MessageType::WARNING,
+                    format!("could not register for configuration changes: {e}"),
+                )
+                .await;
+        }
     }
 
     async fn shutdown(&self) -> Result<()> {
         Ok(())
     }
 
+    async fn did_change_configuration(&self, params: DidChangeConfigurationParams) {
+        *self.config.lock().await = Config::from_value(params.settings);
+    }
+
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        let mut docs = self.documents.lock().await;
-        docs.insert(
-            params.text_document.uri.clone(),
-            params.text_document.text.clone(),
-        );
+        let uri = params.text_document.uri.clone();
+        let version = params.text_document.version;
+        {
+            let mut docs = self.documents.lock().await;
+            docs.insert(
+                uri.clone(),
+                Document {
+                    text: params.text_document.text.clone(),
+                    version,
+                },
+            );
+        }
+        self.publish_diagnostics(&uri, version).await;
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        let mut docs = self.documents.lock().await;
-        *docs.entry(params.text_document.uri.clone()).or_default() =
-            params.content_changes[0].text.clone();
+        let encoding = *self.position_encoding.lock().await;
+        let uri = params.text_document.uri.clone();
+        let version = {
+            let mut docs = self.documents.lock().await;
+            let Some(doc) = docs.get_mut(&uri) else {
+                return;
+            };
+
+            // Changes can arrive out of order over the wire; only apply ones
+            // that move the document forward from the version we last saw.
+            if params.text_document.version <= doc.version {
+                return;
+            }
+
+            apply_content_changes(&mut doc.text, params.content_changes, encoding);
+            doc.version = params.text_document.version;
+            doc.version
+        };
+        self.publish_diagnostics(&uri, version).await;
     }
 
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
@@ -68,8 +144,9 @@ impl LanguageServer for Backend {
     }
 
     async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        let config = self.config.lock().await;
         let mut docs = self.documents.lock().await;
-        let doc = docs.entry(params.text_document.uri.clone()).or_default();
+        let doc = &docs.entry(params.text_document.uri.clone()).or_default().text;
 
         let whole_doc = Range::new(
             Position {
@@ -82,7 +159,7 @@ impl LanguageServer for Backend {
             },
         );
 
-        let new_doc = reformat_entire_doc(doc);
+        let new_doc = reformat_entire_doc(doc, &config);
 
         Ok(Some(vec![TextEdit {
             range: whole_doc,
@@ -94,12 +171,15 @@ impl LanguageServer for Backend {
         &self,
         params: DocumentRangeFormattingParams,
     ) -> Result<Option<Vec<TextEdit>>> {
+        let encoding = *self.position_encoding.lock().await;
+        let config = self.config.lock().await;
         let mut docs = self.documents.lock().await;
         let range = params.range;
         // eprintln!("{range:?}");
         let Some(doc) = docs.get_mut(&params.text_document.uri) else {
             return Ok(None);
         };
+        let doc = &mut doc.text;
 
         let mut content = doc
             .lines()
@@ -112,16 +192,25 @@ impl LanguageServer for Backend {
         }
 
         // eprintln!("Before: {content:?}");
-        content[0] = &content[0][range.start.character as usize..];
         let last = content.len() - 1;
-        content[last] = &content[last][..range.end.character as usize];
+        let start_byte = encoding.char_to_byte_in_line(content[0], range.start.character);
+        let end_byte = encoding.char_to_byte_in_line(content[last], range.end.character);
+        if last == 0 {
+            // Both offsets are measured against the same original line, so
+            // slice it once with both rather than applying the end offset
+            // to the line after the start has already been cut off.
+            content[0] = &content[0][start_byte..end_byte.max(start_byte)];
+        } else {
+            content[0] = &content[0][start_byte..];
+            content[last] = &content[last][..end_byte];
+        }
         // eprintln!("after: {content:?}");
 
         let leading_blanks = content.iter().take_while(|s| s.is_empty()).count();
         let trailing_blanks = content.iter().rev().take_while(|s| s.is_empty()).count();
         // eprintln!("{leading_blanks} {trailing_blanks}");
 
-        let new_text = reformat(&content);
+        let new_text = reformat_body(&content, &config);
         let mut ret = String::new();
         (0..leading_blanks).for_each(|_| ret.push('\n'));
         ret.push_str(new_text.trim());
@@ -131,26 +220,103 @@ impl LanguageServer for Backend {
             new_text: ret,
         }]))
     }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let encoding = *self.position_encoding.lock().await;
+        let config = self.config.lock().await.clone();
+        let docs = self.documents.lock().await;
+        let Some(doc) = docs.get(&params.text_document.uri) else {
+            return Ok(None);
+        };
+
+        let actions = code_actions::actions_for(
+            &params.text_document.uri,
+            &doc.text,
+            params.range,
+            encoding,
+            &config,
+        );
+        Ok(Some(actions))
+    }
+}
+
+// Apply `changes` to `text` in order. Per the LSP spec each
+// `TextDocumentContentChangeEvent`'s range is expressed against the
+// document as it stood after the previous change in the same batch was
+// applied, not against the original text, so these must be spliced in
+// sequentially rather than computed from one snapshot.
+fn apply_content_changes(
+    text: &mut String,
+    changes: Vec<TextDocumentContentChangeEvent>,
+    encoding: PositionEncoding,
+) {
+    for change in changes {
+        match change.range {
+            Some(range) => {
+                let start = encoding.position_to_byte_offset(text, range.start);
+                let end = encoding.position_to_byte_offset(text, range.end);
+                text.replace_range(start..end, &change.text);
+            }
+            None => *text = change.text,
+        }
+    }
+}
+
+// The default column at which body lines get wrapped; see
+// `Config::line_length`.
+pub(crate) const LINE_LENGTH: usize = 78;
+
+// How deeply a line is quoted according to `quote_prefix`, e.g. with the
+// default "> " prefix "> > foo" is level 2.
+pub(crate) fn quote_level(line: &str, quote_prefix: &str) -> usize {
+    strip_quote_markers(line, quote_prefix).0
+}
+
+// Strip the quote markers off the front of `line` -- one repetition of
+// `quote_prefix` (trailing spaces trimmed) per quote level, each optionally
+// followed by one more space -- returning the depth found and what's left.
+// Lines already quoted with a bare '>' still count even after
+// `quote_prefix` has been changed to something else, e.g. a mail quoted
+// before a mailing list switched to French-style "» " quoting, so
+// switching prefixes doesn't orphan existing quoting.
+pub(crate) fn strip_quote_markers<'a>(line: &'a str, quote_prefix: &str) -> (usize, &'a str) {
+    let marker = quote_prefix.trim_end_matches(' ');
+    let marker = if marker.is_empty() { ">" } else { marker };
+
+    let mut depth = 0;
+    let mut rest = line;
+    while let Some(stripped) = rest.strip_prefix(marker) {
+        rest = stripped.strip_prefix(' ').unwrap_or(stripped);
+        depth += 1;
+    }
+
+    if depth == 0 && marker != ">" {
+        return strip_quote_markers(line, ">");
+    }
+
+    (depth, rest)
+}
+
+// Reflow `input`, picking the format=flowed aware algorithm when the user
+// has opted into it and the plain greedy-rejoin one otherwise.
+pub(crate) fn reformat_body(input: &[&str], config: &Config) -> String {
+    if config.detect_format_flowed {
+        flowed::reformat_flowed(input, config)
+    } else {
+        reformat(input, config)
+    }
 }
 
 // Reformat the input string, being intelligent about quoting
 // so lines which start with `> *` get grouped.  Essentially
 // we track a "level" for each line and then flow groups
 // at the same level, and replace things to look neat
-fn reformat(input: &[&str]) -> String {
+fn reformat(input: &[&str], config: &Config) -> String {
     let input = input
         .iter()
         .map(|l| {
-            let level = l
-                .chars()
-                .take_while(|&c| c == ' ' || c == '>')
-                .filter(|&c| c == '>')
-                .count();
-            let rest = l
-                .chars()
-                .skip_while(|&c| c == ' ' || c == '>')
-                .collect::<String>();
-            (level, rest)
+            let (level, rest) = strip_quote_markers(l, &config.quote_prefix);
+            (level, rest.to_string())
         })
         .collect::<Vec<_>>();
     // eprintln!("{input:?}");
@@ -163,11 +329,11 @@ fn reformat(input: &[&str]) -> String {
         if part.is_empty() {
             // Something already present, wrap that into the output
             if let Some(curlevel) = curlevel {
-                do_wrap(&mut ret, &acc, curlevel);
+                do_wrap(&mut ret, &acc, curlevel, config);
             }
             curlevel = None;
             // Blank line
-            do_wrap(&mut ret, "", level);
+            do_wrap(&mut ret, "", level, config);
             continue;
         }
         match curlevel {
@@ -180,7 +346,7 @@ fn reformat(input: &[&str]) -> String {
                 acc.push_str(part);
             }
             Some(ol) => {
-                do_wrap(&mut ret, &acc, ol);
+                do_wrap(&mut ret, &acc, ol, config);
                 curlevel = Some(level);
                 acc = part.to_string();
             }
@@ -188,19 +354,18 @@ fn reformat(input: &[&str]) -> String {
     }
 
     if let Some(level) = curlevel {
-        do_wrap(&mut ret, &acc, level);
+        do_wrap(&mut ret, &acc, level, config);
     }
 
     ret
 }
 
-fn do_wrap(ret: &mut String, acc: &str, curlevel: usize) {
-    let level_str = "> ".repeat(curlevel);
-    const LINE_LENGTH: usize = 78;
+fn do_wrap(ret: &mut String, acc: &str, curlevel: usize, config: &Config) {
+    let level_str = config.quote_prefix.repeat(curlevel);
 
     let mut lineacc = level_str.clone();
     for word in acc.trim().split_ascii_whitespace() {
-        if lineacc.len() + word.len() > LINE_LENGTH && lineacc.len() > level_str.len() {
+        if lineacc.len() + word.len() > config.line_length && lineacc.len() > level_str.len() {
             ret.push_str(lineacc.trim());
             ret.push('\n');
             lineacc = level_str.clone();
@@ -213,22 +378,26 @@ fn do_wrap(ret: &mut String, acc: &str, curlevel: usize) {
     ret.push('\n');
 }
 
-fn reformat_entire_doc(body_s: &str) -> String {
-    enum ParseState {
-        Header,
-        Body,
-        Signature,
-    }
-    use ParseState::*;
-    let mut header = vec![];
-    let mut body = vec![];
-    let mut sig = vec![];
+// Which part of the message a line belongs to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum LineKind {
+    Header,
+    Body,
+    Signature,
+}
 
+// Walk the document line by line, tagging each with the part of the
+// message it belongs to. Shared by `reformat_entire_doc` (to know what to
+// reflow) and the diagnostics pass (to know what to flag).
+pub(crate) fn classify_lines(body_s: &str) -> Vec<(LineKind, &str)> {
+    use LineKind::*;
     let mut state = Header;
+    let mut out = vec![];
+
     for line in body_s.lines() {
         match state {
             Header => {
-                header.push(line);
+                out.push((Header, line));
                 if line.is_empty() {
                     state = Body;
                 }
@@ -236,19 +405,53 @@ fn reformat_entire_doc(body_s: &str) -> String {
             Body => {
                 if line == "--" || line == "-- " {
                     state = Signature;
-                    sig.push(line);
+                    out.push((Signature, line));
                 } else {
-                    body.push(line);
+                    out.push((Body, line));
                 }
             }
             Signature => {
-                sig.push(line);
+                out.push((Signature, line));
             }
         }
     }
-    let new_body = reformat(&body);
+    out
+}
+
+fn reformat_entire_doc(body_s: &str, config: &Config) -> String {
+    use LineKind::*;
+    let classified = classify_lines(body_s);
+    let header = classified
+        .iter()
+        .filter(|(k, _)| *k == Header)
+        .map(|(_, l)| *l)
+        .collect::<Vec<_>>();
+    let body = classified
+        .iter()
+        .filter(|(k, _)| *k == Body)
+        .map(|(_, l)| *l)
+        .collect::<Vec<_>>();
+    let sig = classified
+        .iter()
+        .filter(|(k, _)| *k == Signature)
+        .map(|(_, l)| *l)
+        .collect::<Vec<_>>();
+    let new_body = reformat_body(&body, config);
     let header = header.join("\n");
-    let sig = sig.join("\n");
+    let sig = if config.reflow_signature {
+        // `sig` includes the "-- " delimiter line itself; reflowing it
+        // along with the body would merge it into the first line of text
+        // and destroy the on-its-own-line delimiter MUAs rely on to strip
+        // signatures, so only the lines beneath it are reflowed.
+        match sig.split_first() {
+            Some((delim, rest)) if !rest.is_empty() => {
+                format!("{delim}\n{}", reformat_body(rest, config))
+            }
+            _ => sig.join("\n"),
+        }
+    } else {
+        sig.join("\n")
+    };
     format!("{header}\n{new_body}{sig}\n")
 }
 
@@ -257,8 +460,25 @@ impl Backend {
         Self {
             client,
             documents: Mutex::new(HashMap::new()),
+            position_encoding: Mutex::new(PositionEncoding::Utf16),
+            config: Mutex::new(Config::default()),
         }
     }
+
+    async fn publish_diagnostics(&self, uri: &Url, version: i32) {
+        let diagnostics = {
+            let encoding = *self.position_encoding.lock().await;
+            let config = self.config.lock().await;
+            let docs = self.documents.lock().await;
+            let Some(doc) = docs.get(uri) else {
+                return;
+            };
+            diagnostics::analyze(&doc.text, &config, encoding)
+        };
+        self.client
+            .publish_diagnostics(uri.clone(), diagnostics, Some(version))
+            .await;
+    }
 }
 
 #[tokio::main]
@@ -270,3 +490,49 @@ async fn main() {
 
     Server::new(stdin, stdout, socket).serve(service).await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn change(start: (u32, u32), end: (u32, u32), text: &str) -> TextDocumentContentChangeEvent {
+        TextDocumentContentChangeEvent {
+            range: Some(Range::new(
+                Position::new(start.0, start.1),
+                Position::new(end.0, end.1),
+            )),
+            range_length: None,
+            text: text.to_string(),
+        }
+    }
+
+    #[test]
+    fn applies_changes_in_order_against_the_running_text() {
+        // The second change's range (line 0, columns 6..14) only makes
+        // sense once the first change has already replaced "world" with
+        // "everyone" -- against the original text column 14 lies past the
+        // end of "hello world", so the wrong span would be replaced.
+        let mut text = "hello world\n".to_string();
+        let changes = vec![
+            change((0, 6), (0, 11), "everyone"),
+            change((0, 6), (0, 14), "friend"),
+        ];
+        apply_content_changes(&mut text, changes, PositionEncoding::Utf16);
+        assert_eq!(text, "hello friend\n");
+    }
+
+    #[test]
+    fn full_replacement_change_ignores_prior_range_edits() {
+        let mut text = "hello world\n".to_string();
+        let changes = vec![
+            change((0, 6), (0, 11), "there"),
+            TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: "replaced entirely".to_string(),
+            },
+        ];
+        apply_content_changes(&mut text, changes, PositionEncoding::Utf16);
+        assert_eq!(text, "replaced entirely");
+    }
+}